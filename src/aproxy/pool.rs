@@ -0,0 +1,186 @@
+use std::{sync::Arc, time::Instant};
+
+use rustls_pki_types::ServerName;
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    spawn,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{
+    aproxy::{
+        resolver::{CachingResolver, DohResolver, SystemResolver},
+        tcp::{happy_eyeballs_connect, resolve_happy_eyeballs_order},
+    },
+    config::OPTIONS,
+    types::Result,
+};
+
+struct Idle {
+    stream: TlsStream<TcpStream>,
+    handshaked_at: Instant,
+}
+
+/// A pool of pre-warmed, handshaked TLS connections to the upstream trojan server.
+///
+/// Connections sit idle with the TLS handshake already done but no Trojan request written
+/// yet, so `start_tcp_proxy` only has to pop one and write the request header. A background
+/// task keeps the pool topped back up to its target size.
+pub struct ConnectionPool {
+    idle: Arc<Mutex<Vec<Idle>>>,
+    hostname: String,
+    port: u16,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    target_size: usize,
+    max_idle_lifetime: Duration,
+    resolver: CachingResolver,
+}
+
+impl ConnectionPool {
+    pub fn new(
+        hostname: String,
+        port: u16,
+        server_name: ServerName<'static>,
+        connector: TlsConnector,
+    ) -> ConnectionPool {
+        let doh_url = OPTIONS.proxy_args().doh_url.clone();
+        let resolver = CachingResolver::new(if doh_url.is_empty() {
+            Arc::new(SystemResolver)
+        } else {
+            Arc::new(DohResolver::new(doh_url))
+        });
+        ConnectionPool {
+            idle: Arc::new(Mutex::new(Vec::new())),
+            hostname,
+            port,
+            server_name,
+            connector,
+            target_size: OPTIONS.proxy_args().pool_size,
+            max_idle_lifetime: Duration::from_secs(OPTIONS.proxy_args().pool_max_idle_secs),
+            resolver,
+        }
+    }
+
+    /// spawns the background task that keeps the pool topped up
+    pub fn spawn_refill(self: &Arc<Self>) {
+        let pool = self.clone();
+        spawn(async move {
+            loop {
+                pool.refill().await;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        });
+    }
+
+    async fn connect_upstream(&self) -> Result<TcpStream> {
+        let addrs =
+            resolve_happy_eyeballs_order(&self.resolver, self.hostname.as_str(), self.port)
+                .await?;
+        let attempt_delay =
+            Duration::from_millis(OPTIONS.proxy_args().happy_eyeballs_attempt_delay_millis);
+        let remote = happy_eyeballs_connect(&addrs, attempt_delay).await?;
+        remote.set_nodelay(true)?;
+        Ok(remote)
+    }
+
+    async fn dial(&self) -> Result<TlsStream<TcpStream>> {
+        let remote = self.connect_upstream().await?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), remote)
+            .await?;
+        Ok(stream)
+    }
+
+    async fn refill(&self) {
+        let deficit = {
+            let mut idle = self.idle.lock().await;
+            idle.retain(|conn| conn.handshaked_at.elapsed() < self.max_idle_lifetime);
+            self.target_size.saturating_sub(idle.len())
+        };
+        for _ in 0..deficit {
+            match self.dial().await {
+                Ok(stream) => {
+                    self.idle.lock().await.push(Idle {
+                        stream,
+                        handshaked_at: Instant::now(),
+                    });
+                }
+                Err(err) => {
+                    log::warn!("pre-warm connection to {} failed:{}", self.hostname, err);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// pops a warm, already-handshaked connection if one is available
+    pub async fn try_pop_warm(&self) -> Option<TlsStream<TcpStream>> {
+        let mut idle = self.idle.lock().await;
+        loop {
+            match idle.pop() {
+                Some(conn) if conn.handshaked_at.elapsed() >= self.max_idle_lifetime => {
+                    log::debug!("dropping stale pooled connection to {}", self.hostname);
+                    continue;
+                }
+                other => break other.map(|conn| conn.stream),
+            }
+        }
+    }
+
+    /// dials a fresh connection, sending `early_data` as TLS 1.3 0-RTT data when the
+    /// connector supports it; falls back to writing `early_data` once the handshake is
+    /// actually established if the server declines to resume the session.
+    pub async fn dial_with_early_data(
+        &self,
+        early_data: &[u8],
+    ) -> Result<TlsStream<TcpStream>> {
+        if !OPTIONS.proxy_args().enable_early_data {
+            let mut stream = self.dial().await?;
+            stream.write_all(early_data).await?;
+            return Ok(stream);
+        }
+        let remote = self.connect_upstream().await?;
+        let connector = self.connector.clone().early_data(true);
+        // `into_fallible` returns before the handshake finishes, so the writer it hands back
+        // rides along as 0-RTT data on the ClientHello flight rather than after a full round
+        // trip. Only fall back to a second write if the server actually declined to resume.
+        match connector
+            .connect(self.server_name.clone(), remote)
+            .into_fallible()
+            .await
+        {
+            Ok(mut early) => {
+                if let Some(mut writer) = early.early_data() {
+                    writer.write_all(early_data).await?;
+                }
+                let mut stream = early.await?;
+                if !stream.get_ref().1.is_early_data_accepted() {
+                    log::debug!(
+                        "early data rejected by {}, resending trojan request",
+                        self.hostname
+                    );
+                    stream.write_all(early_data).await?;
+                }
+                Ok(stream)
+            }
+            Err((err, remote)) => {
+                log::debug!(
+                    "0-RTT handshake to {} failed:{}, falling back to a normal handshake",
+                    self.hostname,
+                    err
+                );
+                let mut stream = self
+                    .connector
+                    .connect(self.server_name.clone(), remote)
+                    .await?;
+                stream.write_all(early_data).await?;
+                Ok(stream)
+            }
+        }
+    }
+}
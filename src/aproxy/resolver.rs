@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rustls::{ClientConfig, RootCertStore};
+use rustls_pki_types::ServerName;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+    sync::Mutex,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{config::OPTIONS, types::Result};
+
+type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<IpAddr>>> + Send + 'a>>;
+
+/// Abstraction over "turn a hostname into addresses", mirroring the server-side
+/// `DnsResolver` so the client can swap the system resolver for an encrypted one without
+/// touching the dialing code.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a>;
+}
+
+/// Resolves through the OS's `getaddrinfo`, same as a plain `TcpStream::connect` would.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let addrs = lookup_host((host, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect();
+            Ok(addrs)
+        })
+    }
+}
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+
+/// Resolves over DNS-over-HTTPS (RFC 8484), so the upstream trojan server's name is sent to
+/// `doh_url` over TLS instead of leaking to the local network resolver in plaintext.
+pub struct DohResolver {
+    host: String,
+    port: u16,
+    path: String,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+impl DohResolver {
+    pub fn new(doh_url: String) -> DohResolver {
+        let (host, port, path) = split_doh_url(&doh_url);
+        let mut root_store = RootCertStore::empty();
+        root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = ClientConfig::builder()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(host.clone())
+            .unwrap_or_else(|_| ServerName::try_from("1.1.1.1".to_owned()).unwrap());
+        DohResolver {
+            host,
+            port,
+            path,
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+        }
+    }
+
+    async fn query(&self, host: &str, qtype: u16) -> Result<Vec<IpAddr>> {
+        let request = build_dns_query(host, qtype);
+        let body = base64url_encode(&request);
+        let http_request = format!(
+            "GET {}?dns={} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Accept: application/dns-message\r\n\
+             Connection: close\r\n\r\n",
+            self.path, body, self.host
+        );
+
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let mut stream = self
+            .connector
+            .connect(self.server_name.clone(), tcp)
+            .await?;
+        stream.write_all(http_request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let split_at =
+            find_header_end(&response).ok_or("malformed DoH http response")?;
+        parse_dns_answers(&response[split_at..])
+    }
+}
+
+impl Resolver for DohResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            log::debug!("resolving {} via DoH endpoint {}", host, self.host);
+            let (v4, v6) = tokio::join!(self.query(host, DNS_TYPE_A), self.query(host, DNS_TYPE_AAAA));
+            let mut addrs = Vec::new();
+            match v6 {
+                Ok(mut ips) => addrs.append(&mut ips),
+                Err(err) => log::debug!("DoH AAAA query for {} failed:{}", host, err),
+            }
+            match v4 {
+                Ok(mut ips) => addrs.append(&mut ips),
+                Err(err) => log::debug!("DoH A query for {} failed:{}", host, err),
+            }
+            if addrs.is_empty() {
+                return Err(format!("DoH resolution for {} returned no addresses", host).into());
+            }
+            Ok(addrs)
+        })
+    }
+}
+
+/// splits `https://host[:port]/path` into its parts, defaulting the path to `/dns-query` and
+/// the port to 443 the way every public DoH provider expects.
+fn split_doh_url(doh_url: &str) -> (String, u16, String) {
+    let rest = doh_url
+        .strip_prefix("https://")
+        .or_else(|| doh_url.strip_prefix("http://"))
+        .unwrap_or(doh_url);
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/dns-query"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host.to_owned(), port.parse().unwrap_or(443)),
+        None => (authority.to_owned(), 443),
+    };
+    let path = if path.is_empty() {
+        "/dns-query".to_owned()
+    } else {
+        path.to_owned()
+    };
+    (host, port, path)
+}
+
+fn encode_qname(name: &str, out: &mut Vec<u8>) {
+    for label in name.trim_end_matches('.').split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// builds a minimal standard DNS query message, per RFC 1035 section 4.1.
+fn build_dns_query(name: &str, qtype: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(32 + name.len());
+    msg.extend_from_slice(&[0x00, 0x00]); // ID: 0, so the GET request stays cache-friendly
+    msg.extend_from_slice(&[0x01, 0x00]); // flags: recursion desired
+    msg.extend_from_slice(&[0x00, 0x01]); // QDCOUNT
+    msg.extend_from_slice(&[0x00, 0x00]); // ANCOUNT
+    msg.extend_from_slice(&[0x00, 0x00]); // NSCOUNT
+    msg.extend_from_slice(&[0x00, 0x00]); // ARCOUNT
+    encode_qname(name, &mut msg);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    msg
+}
+
+fn base64url_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32);
+        out.push(ALPHABET[(n >> 18) as usize & 0x3f] as char);
+        out.push(ALPHABET[(n >> 12) as usize & 0x3f] as char);
+        if chunk.len() > 1 {
+            out.push(ALPHABET[(n >> 6) as usize & 0x3f] as char);
+        }
+        if chunk.len() > 2 {
+            out.push(ALPHABET[n as usize & 0x3f] as char);
+        }
+    }
+    out
+}
+
+fn find_header_end(response: &[u8]) -> Option<usize> {
+    response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|idx| idx + 4)
+}
+
+/// skips one encoded DNS name starting at `pos`, returning the offset just past it. Handles plain labels; a compression pointer (top two bits set) ends a name.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len;
+    }
+}
+
+/// parses the answer section of a DNS response message and returns every A/AAAA record found.
+fn parse_dns_answers(msg: &[u8]) -> Result<Vec<IpAddr>> {
+    if msg.len() < 12 {
+        return Err("DoH response too short".into());
+    }
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]) as usize;
+    let ancount = u16::from_be_bytes([msg[6], msg[7]]) as usize;
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(msg, pos).ok_or("malformed DoH question section")?;
+        pos += 4; // qtype + qclass
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..ancount {
+        pos = skip_name(msg, pos).ok_or("malformed DoH answer name")?;
+        if pos + 10 > msg.len() {
+            return Err("malformed DoH answer record".into());
+        }
+        let rtype = u16::from_be_bytes([msg[pos], msg[pos + 1]]);
+        let rdlength = u16::from_be_bytes([msg[pos + 8], msg[pos + 9]]) as usize;
+        let rdata_start = pos + 10;
+        pos = rdata_start + rdlength;
+        if pos > msg.len() {
+            return Err("malformed DoH answer rdata".into());
+        }
+        match (rtype, rdlength) {
+            (t, 4) if t == DNS_TYPE_A => {
+                let rdata = &msg[rdata_start..rdata_start + 4];
+                addrs.push(IpAddr::V4(Ipv4Addr::new(
+                    rdata[0], rdata[1], rdata[2], rdata[3],
+                )));
+            }
+            (t, 16) if t == DNS_TYPE_AAAA => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&msg[rdata_start..rdata_start + 16]);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+    Ok(addrs)
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+/// Caches resolutions in-process, honoring a TTL, and lets the user pin host -> IP overrides
+/// so the upstream server's name never has to hit a resolver at all.
+pub struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    overrides: HashMap<String, Vec<IpAddr>>,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl CachingResolver {
+    pub fn new(inner: Arc<dyn Resolver>) -> CachingResolver {
+        CachingResolver {
+            inner,
+            overrides: OPTIONS.proxy_args().resolver_overrides.clone(),
+            cache: Mutex::new(HashMap::new()),
+            ttl: Duration::from_secs(OPTIONS.proxy_args().resolver_cache_ttl_secs),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Some(addrs) = self.overrides.get(host) {
+            return Ok(addrs.clone());
+        }
+
+        if let Some(entry) = self.cache.lock().await.get(host) {
+            if Instant::now() < entry.expires_at {
+                return Ok(entry.addrs.clone());
+            }
+        }
+
+        let result = self.inner.resolve(host).await;
+        let mut cache = self.cache.lock().await;
+        match &result {
+            Ok(addrs) => {
+                cache.insert(
+                    host.to_owned(),
+                    CacheEntry {
+                        addrs: addrs.clone(),
+                        expires_at: Instant::now() + self.ttl,
+                    },
+                );
+            }
+            Err(_) => {
+                cache.insert(
+                    host.to_owned(),
+                    CacheEntry {
+                        addrs: Vec::new(),
+                        expires_at: Instant::now() + self.negative_ttl,
+                    },
+                );
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64url_encode_matches_known_vectors() {
+        assert_eq!(base64url_encode(b""), "");
+        assert_eq!(base64url_encode(b"f"), "Zg");
+        assert_eq!(base64url_encode(b"fo"), "Zm8");
+        assert_eq!(base64url_encode(b"foo"), "Zm9v");
+        assert_eq!(base64url_encode(b"foob"), "Zm9vYg");
+        assert_eq!(base64url_encode(b"fooba"), "Zm9vYmE");
+        assert_eq!(base64url_encode(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn build_dns_query_encodes_header_and_qname() {
+        let query = build_dns_query("example.com", DNS_TYPE_A);
+        assert_eq!(&query[0..2], &[0x00, 0x00], "ID must stay 0 for cache friendliness");
+        assert_eq!(&query[2..4], &[0x01, 0x00], "recursion desired flag");
+        assert_eq!(&query[4..6], &[0x00, 0x01], "QDCOUNT must be 1");
+        // qname: 7"example" 3"com" 0
+        let qname_start = 12;
+        assert_eq!(query[qname_start], 7);
+        assert_eq!(&query[qname_start + 1..qname_start + 8], b"example");
+        assert_eq!(query[qname_start + 8], 3);
+        assert_eq!(&query[qname_start + 9..qname_start + 12], b"com");
+        assert_eq!(query[qname_start + 12], 0);
+        let qtype_start = qname_start + 13;
+        assert_eq!(&query[qtype_start..qtype_start + 2], &DNS_TYPE_A.to_be_bytes());
+        assert_eq!(&query[qtype_start + 2..qtype_start + 4], &DNS_CLASS_IN.to_be_bytes());
+    }
+
+    #[test]
+    fn skip_name_stops_after_the_terminating_zero_label() {
+        let mut msg = Vec::new();
+        encode_qname("a.b", &mut msg);
+        msg.push(0xaa); // trailing byte that skip_name must not consume
+        assert_eq!(skip_name(&msg, 0), Some(msg.len() - 1));
+    }
+
+    #[test]
+    fn skip_name_stops_at_a_compression_pointer() {
+        let msg = [0xc0, 0x0c, 0xaa];
+        assert_eq!(skip_name(&msg, 0), Some(2));
+    }
+
+    fn sample_response() -> Vec<u8> {
+        let mut msg = build_dns_query("example.com", DNS_TYPE_A);
+        msg[6] = 0x00;
+        msg[7] = 0x02; // ANCOUNT = 2
+
+        // answer 1: A record pointing back at the question's name via a compression pointer
+        msg.extend_from_slice(&[0xc0, 0x0c]);
+        msg.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+        msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 60]); // TTL
+        msg.extend_from_slice(&4u16.to_be_bytes()); // RDLENGTH
+        msg.extend_from_slice(&[93, 184, 216, 34]); // 93.184.216.34
+
+        // answer 2: AAAA record, same name pointer
+        msg.extend_from_slice(&[0xc0, 0x0c]);
+        msg.extend_from_slice(&DNS_TYPE_AAAA.to_be_bytes());
+        msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 60]);
+        msg.extend_from_slice(&16u16.to_be_bytes());
+        msg.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        msg
+    }
+
+    #[test]
+    fn parse_dns_answers_extracts_a_and_aaaa_records() {
+        let addrs = parse_dns_answers(&sample_response()).unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0x0db8, 0, 0, 0, 0, 0, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_dns_answers_rejects_a_truncated_message() {
+        assert!(parse_dns_answers(&[0u8; 4]).is_err());
+    }
+
+    #[test]
+    fn split_doh_url_defaults_path_and_port() {
+        assert_eq!(
+            split_doh_url("https://dns.example.com/dns-query"),
+            ("dns.example.com".to_owned(), 443, "/dns-query".to_owned())
+        );
+        assert_eq!(
+            split_doh_url("https://dns.example.com:8443"),
+            ("dns.example.com".to_owned(), 8443, "/dns-query".to_owned())
+        );
+    }
+}
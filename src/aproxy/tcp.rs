@@ -4,6 +4,7 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 use bytes::BytesMut;
@@ -13,11 +14,12 @@ use tokio::{
     net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
     spawn,
     sync::mpsc::UnboundedSender,
+    task::JoinSet,
 };
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use crate::{
-    aproxy::wait_until_stop,
+    aproxy::{pool::ConnectionPool, resolver::CachingResolver, wait_until_stop},
     async_utils::copy,
     config::OPTIONS,
     proto::{TrojanRequest, CONNECT},
@@ -25,12 +27,101 @@ use crate::{
     types::Result,
 };
 
+/// Resolves `hostname` through `resolver` and interleaves the results so the first attempt
+/// prefers IPv6, e.g. `[v6, v4, v6, v4, ...]`.
+pub(crate) async fn resolve_happy_eyeballs_order(
+    resolver: &CachingResolver,
+    hostname: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>> {
+    let resolved: Vec<SocketAddr> = resolver
+        .resolve(hostname)
+        .await?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    Ok(interleave_dual_stack(resolved))
+}
+
+/// interleaves a mix of v4/v6 addresses as `[v6, v4, v6, v4, ...]`, preferring v6 first per
+/// RFC 8305, and simply appending whichever family runs out the other's leftovers.
+fn interleave_dual_stack(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        if !v6.is_empty() {
+            ordered.push(v6.remove(0));
+        }
+        if !v4.is_empty() {
+            ordered.push(v4.remove(0));
+        }
+    }
+    ordered
+}
+
+/// RFC 8305 Happy Eyeballs: starts a connection attempt to `addrs[0]`, and if it hasn't
+/// finished within `attempt_delay`, starts the next address concurrently while earlier
+/// attempts keep running. The first socket to finish its handshake wins; the rest are
+/// dropped, cancelling their in-flight attempts.
+pub(crate) async fn happy_eyeballs_connect(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+) -> Result<TcpStream> {
+    if addrs.is_empty() {
+        return Err("no addresses to connect to".into());
+    }
+    let mut attempts: JoinSet<(SocketAddr, std::io::Result<TcpStream>)> = JoinSet::new();
+    let mut next = 0;
+    let mut spawn_next = |attempts: &mut JoinSet<_>, next: &mut usize| {
+        if let Some(&addr) = addrs.get(*next) {
+            *next += 1;
+            attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+        }
+    };
+    spawn_next(&mut attempts, &mut next);
+    let mut last_err = None;
+    loop {
+        tokio::select! {
+            biased;
+            Some(joined) = attempts.join_next(), if !attempts.is_empty() => {
+                match joined {
+                    Ok((addr, Ok(stream))) => {
+                        log::debug!("happy eyeballs connected via {}", addr);
+                        return Ok(stream);
+                    }
+                    Ok((addr, Err(err))) => {
+                        log::debug!("happy eyeballs attempt to {} failed:{}", addr, err);
+                        last_err = Some(err);
+                    }
+                    Err(_) => {}
+                }
+                if attempts.is_empty() && next >= addrs.len() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep(attempt_delay), if next < addrs.len() => {
+                spawn_next(&mut attempts, &mut next);
+            }
+        }
+    }
+    Err(last_err
+        .map(Into::into)
+        .unwrap_or_else(|| "all connection attempts failed".into()))
+}
+
 pub async fn run_tcp(
     listener: TcpListener,
     server_name: ServerName<'static>,
     connector: TlsConnector,
     sender: Option<UnboundedSender<IpAddr>>,
 ) -> Result<()> {
+    let pool = Arc::new(ConnectionPool::new(
+        OPTIONS.proxy_args().hostname.clone(),
+        OPTIONS.proxy_args().port,
+        server_name,
+        connector,
+    ));
+    pool.spawn_refill();
     loop {
         let (client, _) = listener.accept().await?;
         let dst_addr = sys::get_oridst_addr(&client)?;
@@ -38,30 +129,39 @@ pub async fn run_tcp(
             sender.send(dst_addr.ip())?;
         }
         client.set_nodelay(true)?;
-        spawn(start_tcp_proxy(
-            client,
-            server_name.clone(),
-            connector.clone(),
-            dst_addr,
-        ));
+        spawn(start_tcp_proxy(client, pool.clone(), dst_addr));
     }
 }
 
 async fn start_tcp_proxy(
     mut local: TcpStream,
-    server_name: ServerName<'static>,
-    connector: TlsConnector,
+    pool: Arc<ConnectionPool>,
     dst_addr: SocketAddr,
 ) -> Result<()> {
-    let remote = TcpStream::connect((
-        OPTIONS.proxy_args().hostname.as_str(),
-        OPTIONS.proxy_args().port,
-    ))
-    .await?;
-    let mut remote = connector.connect(server_name, remote).await?;
     let mut request = BytesMut::new();
     TrojanRequest::generate(&mut request, CONNECT, &dst_addr);
-    if let Err(err) = remote.write_all(request.as_ref()).await {
+
+    // A connection popped warm from the pool already finished its handshake without
+    // knowing the destination, so the request is always written explicitly. Only a fresh
+    // dial (pool empty) can ride the request along as TLS 1.3 early data.
+    let (mut remote, request_already_sent) = match pool.try_pop_warm().await {
+        Some(stream) => (stream, false),
+        None => match pool.dial_with_early_data(request.as_ref()).await {
+            Ok(stream) => (stream, true),
+            Err(err) => {
+                log::error!("dial to remote server failed:{}", err);
+                let _ = local.shutdown().await;
+                return Err(err);
+            }
+        },
+    };
+
+    let write_result = if request_already_sent {
+        Ok(())
+    } else {
+        remote.write_all(request.as_ref()).await
+    };
+    if let Err(err) = write_result {
         log::error!("send request to remote server failed:{}", err);
         let _ = remote.shutdown().await;
         let _ = local.shutdown().await;
@@ -97,3 +197,47 @@ async fn local_to_remote(
     copy(local, remote, message, timeout).await;
     running.store(false, Ordering::SeqCst);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn addr(ip: IpAddr, port: u16) -> SocketAddr {
+        SocketAddr::new(ip, port)
+    }
+
+    #[test]
+    fn interleave_dual_stack_prefers_v6_first() {
+        let v4a = addr(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 80);
+        let v4b = addr(IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)), 80);
+        let v6a = addr(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 80);
+        let v6b = addr(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 80);
+        let ordered = interleave_dual_stack(vec![v4a, v4b, v6a, v6b]);
+        assert_eq!(ordered, vec![v6a, v4a, v6b, v4b]);
+    }
+
+    #[test]
+    fn interleave_dual_stack_appends_the_longer_family_leftovers() {
+        let v4 = addr(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 80);
+        let v6 = addr(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 80);
+        let ordered = interleave_dual_stack(vec![v4, v6]);
+        assert_eq!(ordered, vec![v6, v4]);
+
+        let only_v4 = interleave_dual_stack(vec![v4]);
+        assert_eq!(only_v4, vec![v4]);
+    }
+
+    #[test]
+    fn interleave_dual_stack_handles_empty_input() {
+        assert!(interleave_dual_stack(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_connect_rejects_empty_address_list() {
+        assert!(happy_eyeballs_connect(&[], Duration::from_millis(1))
+            .await
+            .is_err());
+    }
+}
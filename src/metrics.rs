@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+/// Cumulative byte/connection counters shared by the udp and tcp backends.
+///
+/// Samples are taken periodically (see [`Metrics::sample`]) to turn the running totals into
+/// a bytes/sec throughput figure without needing a dedicated timer thread; callers already
+/// have a cadence (e.g. the wintun poll loop's `check_duration`) and just call `sample` on it.
+#[derive(Default)]
+pub struct Metrics {
+    bytes_up: AtomicI64,
+    bytes_down: AtomicI64,
+    connections: AtomicUsize,
+    last_bytes_up: AtomicI64,
+    last_bytes_down: AtomicI64,
+}
+
+/// throughput in both directions over the interval since the previous sample, plus the
+/// live connection count at sampling time
+pub struct Throughput {
+    pub up_bytes_per_sec: f64,
+    pub down_bytes_per_sec: f64,
+    pub connections: usize,
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            bytes_up: AtomicI64::new(0),
+            bytes_down: AtomicI64::new(0),
+            connections: AtomicUsize::new(0),
+            last_bytes_up: AtomicI64::new(0),
+            last_bytes_down: AtomicI64::new(0),
+        }
+    }
+
+    pub fn add_up(&self, bytes: usize) {
+        self.bytes_up.fetch_add(bytes as i64, Ordering::Relaxed);
+    }
+
+    pub fn add_down(&self, bytes: usize) {
+        self.bytes_down.fetch_add(bytes as i64, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// computes throughput since the previous call and resets the interval baseline
+    pub fn sample(&self, interval: std::time::Duration) -> Throughput {
+        let up = self.bytes_up.load(Ordering::Relaxed);
+        let down = self.bytes_down.load(Ordering::Relaxed);
+        let last_up = self.last_bytes_up.swap(up, Ordering::Relaxed);
+        let last_down = self.last_bytes_down.swap(down, Ordering::Relaxed);
+        let secs = interval.as_secs_f64().max(f64::EPSILON);
+        Throughput {
+            up_bytes_per_sec: (up - last_up) as f64 / secs,
+            down_bytes_per_sec: (down - last_down) as f64 / secs,
+            connections: self.connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// process-wide counters, sampled periodically and logged by the long-running pollers
+pub static METRICS: Metrics = Metrics::new();
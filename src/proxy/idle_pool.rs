@@ -0,0 +1,220 @@
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use mio::{event::Event, net::TcpStream, Poll, Token};
+use rustls::{ClientConfig, ClientConnection, ServerName};
+
+use crate::{metrics::METRICS, resolver::DnsResolver, tls_conn::TlsConn, types::Result};
+
+/// initial delay before the first reconnect attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+/// reconnect attempts never wait longer than this
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+struct PendingReconnect {
+    next_attempt_at: Instant,
+    backoff: Duration,
+}
+
+impl PendingReconnect {
+    fn new() -> PendingReconnect {
+        PendingReconnect {
+            next_attempt_at: Instant::now() + INITIAL_BACKOFF,
+            backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    fn retry_later(&mut self) {
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        self.next_attempt_at = Instant::now() + self.backoff;
+    }
+}
+
+/// A small pool of pre-established TLS connections to the trojan server.
+///
+/// Connections are indexed the same way the rest of the wintun poll loop indexes sockets:
+/// `token = index * channel_cnt + channel_idle`, so the pool can be driven straight from the
+/// main `mio` event loop alongside the tcp/udp channels.
+///
+/// Every dial made through `connect` is counted in `METRICS`, decremented when it's found
+/// broken while still idle - this is the only client-side source of the "active connections"
+/// figure the wintun poll loop logs. Note this overcounts once `get()` hands a connection off:
+/// nothing here observes a handed-off connection's eventual close, so it stays counted after
+/// it has closed.
+pub struct IdlePool {
+    config: Arc<ClientConfig>,
+    server_name: ServerName,
+    hostname: String,
+    port: u16,
+    pool_size: usize,
+    remote_addr: Option<IpAddr>,
+    channel_cnt: usize,
+    channel_idle: usize,
+    min_index: usize,
+    max_index: usize,
+    next_index: usize,
+    idle: VecDeque<(usize, TlsConn)>,
+    /// connections that broke while sitting idle in the pool and are waiting for a retry,
+    /// keyed by index. Pooled connections never carry application data before `get()` hands
+    /// them off, so there is nothing to replay here beyond redialing.
+    reconnecting: VecDeque<(usize, PendingReconnect)>,
+}
+
+impl IdlePool {
+    pub fn new(
+        config: Arc<ClientConfig>,
+        server_name: ServerName,
+        pool_size: usize,
+        port: u16,
+        hostname: String,
+    ) -> IdlePool {
+        IdlePool {
+            config,
+            server_name,
+            hostname,
+            port,
+            pool_size,
+            remote_addr: None,
+            channel_cnt: 1,
+            channel_idle: 0,
+            min_index: 0,
+            max_index: usize::MAX,
+            next_index: 0,
+            idle: VecDeque::new(),
+            reconnecting: VecDeque::new(),
+        }
+    }
+
+    pub fn init_index(&mut self, channel_cnt: usize, channel_idle: usize, min: usize, max: usize) {
+        self.channel_cnt = channel_cnt;
+        self.channel_idle = channel_idle;
+        self.min_index = min;
+        self.max_index = max;
+        self.next_index = min;
+    }
+
+    /// kicks off DNS resolution for the upstream server and tries to fill the pool
+    pub fn init(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        if self.remote_addr.is_none() {
+            resolver.resolve(self.hostname.clone(), Token(0));
+        }
+        self.fill(poll);
+    }
+
+    /// feeds a resolved address in. Opens no connections itself; the pool is filled on the
+    /// next `ready` or `get` call.
+    pub fn resolve(&mut self, addr: Option<IpAddr>) {
+        if let Some(addr) = addr {
+            self.remote_addr = Some(addr);
+        } else {
+            log::error!("resolve trojan server {} failed", self.hostname);
+        }
+    }
+
+    fn next_index(&mut self) -> usize {
+        let index = self.next_index;
+        self.next_index += 1;
+        if self.next_index > self.max_index {
+            self.next_index = self.min_index;
+        }
+        index
+    }
+
+    fn token_for(&self, index: usize) -> Token {
+        Token(index * self.channel_cnt + self.channel_idle)
+    }
+
+    fn fill(&mut self, poll: &Poll) {
+        let Some(addr) = self.remote_addr else {
+            return;
+        };
+        while self.idle.len() + self.reconnecting.len() < self.pool_size {
+            let index = self.next_index();
+            match self.connect(poll, addr, index) {
+                Ok(conn) => self.idle.push_back((index, conn)),
+                Err(err) => {
+                    log::warn!("connect to trojan server failed:{}, will retry", err);
+                    self.reconnecting.push_back((index, PendingReconnect::new()));
+                    break;
+                }
+            }
+        }
+    }
+
+    fn connect(&mut self, poll: &Poll, addr: IpAddr, index: usize) -> Result<TlsConn> {
+        let stream = TcpStream::connect((addr, self.port).into())?;
+        stream.set_nodelay(true)?;
+        let session = ClientConnection::new(self.config.clone(), self.server_name.clone())?;
+        let token = self.token_for(index);
+        let mut conn = TlsConn::new(index, token, rustls::Connection::Client(session), stream);
+        if !conn.register(poll) {
+            return Err("register idle connection failed".into());
+        }
+        // The only dial point shared by `fill` and `check_reconnect`, so it's the only place
+        // that needs to pair with the `connection_closed` call below - every connection this
+        // pool opens passes through here exactly once.
+        METRICS.connection_opened();
+        Ok(conn)
+    }
+
+    /// drives a readiness event for one of the pooled connections; called from the main
+    /// `mio` loop whenever a `CHANNEL_IDLE` token fires.
+    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+        let index = event.token().0 / self.channel_cnt;
+        if let Some(pos) = self.idle.iter().position(|(i, _)| *i == index) {
+            let (_, conn) = &mut self.idle[pos];
+            conn.do_read();
+            conn.do_send();
+            if conn.closing() {
+                log::warn!("idle connection:{} broke, queueing reconnect", index);
+                self.idle.remove(pos);
+                METRICS.connection_closed();
+                self.reconnecting.push_back((index, PendingReconnect::new()));
+            }
+        }
+        self.fill(poll);
+    }
+
+    /// retries any broken pooled connections whose backoff has elapsed. Must be driven
+    /// periodically from the main poll loop (alongside `tcp_server.check_timeout` and
+    /// `udp_server.check_timeout`) or a broken idle connection never gets redialed and the
+    /// pool permanently shrinks.
+    pub fn check_reconnect(&mut self, poll: &Poll) {
+        let now = Instant::now();
+        let mut still_pending = VecDeque::new();
+        while let Some((index, pending)) = self.reconnecting.pop_front() {
+            if now < pending.next_attempt_at {
+                still_pending.push_back((index, pending));
+                continue;
+            }
+            let Some(addr) = self.remote_addr else {
+                still_pending.push_back((index, pending));
+                continue;
+            };
+            match self.connect(poll, addr, index) {
+                Ok(conn) => {
+                    log::info!("connection:{} reconnected to trojan server", index);
+                    self.idle.push_back((index, conn));
+                }
+                Err(err) => {
+                    log::debug!("reconnect for connection:{} failed:{}, backing off", index, err);
+                    let mut pending = pending;
+                    pending.retry_later();
+                    still_pending.push_back((index, pending));
+                }
+            }
+        }
+        self.reconnecting = still_pending;
+    }
+
+    /// pops a warm, handshaked connection from the pool for a caller to attach to a client flow
+    pub fn get(&mut self, poll: &Poll) -> Option<TlsConn> {
+        let conn = self.idle.pop_front().map(|(_, conn)| conn);
+        self.fill(poll);
+        conn
+    }
+}
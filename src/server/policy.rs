@@ -0,0 +1,161 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use crate::{config::OPTIONS, types::Result};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PolicyMode {
+    /// every destination is reachable
+    Public,
+    /// only destinations matching an entry are reachable
+    Whitelist,
+    /// every destination is reachable except those matching an entry
+    Deny,
+}
+
+struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    fn parse(line: &str) -> Option<Cidr> {
+        let (addr, prefix_len) = line.split_once('/')?;
+        Some(Cidr {
+            addr: addr.trim().parse().ok()?,
+            prefix_len: prefix_len.trim().parse().ok()?,
+        })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = mask_for_u32(self.prefix_len.min(32));
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = mask_for_u128(self.prefix_len.min(128));
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask_for_u32(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix_len as u32)
+    }
+}
+
+fn mask_for_u128(prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        u128::MAX << (128 - prefix_len as u32)
+    }
+}
+
+/// Destination allow/deny policy for the server-side `UdpBackend`, loaded from a plain
+/// CIDR-per-line file, in the same spirit as the client's `add_ipset` route list.
+pub struct DestPolicy {
+    mode: PolicyMode,
+    entries: Vec<Cidr>,
+}
+
+impl DestPolicy {
+    pub fn load() -> Result<DestPolicy> {
+        let args = OPTIONS.udp_backend_args();
+        let mode = args.policy_mode;
+        let entries = match mode {
+            PolicyMode::Public => Vec::new(),
+            PolicyMode::Whitelist | PolicyMode::Deny => {
+                let file = File::open(args.policy_list.as_str())?;
+                BufReader::new(file)
+                    .lines()
+                    .filter_map(|line| line.ok())
+                    .filter_map(|line| Cidr::parse(line.trim()))
+                    .collect()
+            }
+        };
+        Ok(DestPolicy { mode, entries })
+    }
+
+    /// returns true if `addr` is allowed to be relayed to
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        match self.mode {
+            PolicyMode::Public => true,
+            PolicyMode::Whitelist => self.entries.iter().any(|cidr| cidr.contains(addr.ip())),
+            PolicyMode::Deny => !self.entries.iter().any(|cidr| cidr.contains(addr.ip())),
+        }
+    }
+}
+
+static POLICY: OnceLock<DestPolicy> = OnceLock::new();
+
+/// the process-wide destination policy, compiled once from `OPTIONS` on first use
+pub fn global() -> &'static DestPolicy {
+    POLICY.get_or_init(|| {
+        DestPolicy::load().unwrap_or_else(|err| {
+            log::error!("load udp destination policy failed:{}, defaulting to public", err);
+            DestPolicy {
+                mode: PolicyMode::Public,
+                entries: Vec::new(),
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_parse_rejects_malformed_lines() {
+        assert!(Cidr::parse("not-a-cidr").is_none());
+        assert!(Cidr::parse("10.0.0.0").is_none());
+        assert!(Cidr::parse("10.0.0.0/not-a-number").is_none());
+    }
+
+    #[test]
+    fn cidr_contains_matches_v4_prefix() {
+        let cidr = Cidr::parse("10.0.0.0/24").unwrap();
+        assert!(cidr.contains("10.0.0.42".parse().unwrap()));
+        assert!(!cidr.contains("10.0.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_contains_matches_v6_prefix_shorter_than_96() {
+        // a v6 prefix shorter than /96 needs the full 128-bit mask: a 32-bit mask shifted by
+        // `128 - prefix_len` would overflow for any prefix_len < 96.
+        let cidr = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(cidr.contains("2001:db8::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_contains_never_matches_across_address_families() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(!cidr.contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn mask_for_u32_handles_every_prefix_length() {
+        assert_eq!(mask_for_u32(0), 0);
+        assert_eq!(mask_for_u32(24), 0xffffff00);
+        assert_eq!(mask_for_u32(32), u32::MAX);
+    }
+
+    #[test]
+    fn mask_for_u128_handles_every_prefix_length_without_overflow() {
+        assert_eq!(mask_for_u128(0), 0);
+        assert_eq!(mask_for_u128(1), 1u128 << 127);
+        assert_eq!(mask_for_u128(128), u128::MAX);
+    }
+}
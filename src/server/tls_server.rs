@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    net::IpAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -8,12 +9,13 @@ use mio::{event::Event, net::TcpListener, Poll, Token};
 use rustls::{ServerConfig, ServerConnection};
 
 use crate::{
+    config::OPTIONS,
+    metrics::METRICS,
     resolver::DnsResolver,
     server::{connection::Connection, CHANNEL_CNT, CHANNEL_PROXY, MAX_INDEX, MIN_INDEX},
     status::StatusProvider,
     tls_conn::TlsConn,
 };
-use std::net::IpAddr;
 
 pub enum PollEvent<'a> {
     Network(&'a Event),
@@ -34,6 +36,12 @@ pub struct TlsServer {
     config: Arc<ServerConfig>,
     next_id: usize,
     conns: HashMap<usize, Connection>,
+    /// live connection count per peer address, used to enforce `max_connections_per_ip`
+    per_ip_counts: HashMap<IpAddr, usize>,
+    /// peer address for each live connection, so counts can be decremented on removal
+    conn_peers: HashMap<usize, IpAddr>,
+    last_metrics_sample: Instant,
+    metrics_sample_interval: Duration,
 }
 
 pub trait Backend: StatusProvider {
@@ -52,13 +60,38 @@ impl TlsServer {
             config,
             next_id: MIN_INDEX,
             conns: HashMap::new(),
+            per_ip_counts: HashMap::new(),
+            conn_peers: HashMap::new(),
+            last_metrics_sample: Instant::now(),
+            metrics_sample_interval: Duration::from_secs(10),
         }
     }
 
     pub fn accept(&mut self, poll: &Poll) {
         loop {
+            // Stay under the cap by not draining the accept queue any further; leave
+            // sockets pending so the kernel's listen backlog applies backpressure instead
+            // of accepting connections only to immediately drop them.
+            if self.conns.len() >= OPTIONS.tls_server_args().max_connections {
+                log::warn!(
+                    "max_connections:{} reached, pausing accept",
+                    OPTIONS.tls_server_args().max_connections
+                );
+                break;
+            }
             match self.listener.accept() {
                 Ok((stream, addr)) => {
+                    let max_per_ip = OPTIONS.tls_server_args().max_connections_per_ip;
+                    let count = self.per_ip_counts.get(&addr.ip()).copied().unwrap_or(0);
+                    if max_per_ip > 0 && count >= max_per_ip {
+                        log::warn!(
+                            "address:{} exceeded max_connections_per_ip:{}, rejecting",
+                            addr.ip(),
+                            max_per_ip
+                        );
+                        drop(stream);
+                        continue;
+                    }
                     log::debug!(
                         "get new connection, token:{}, address:{}",
                         self.next_id,
@@ -79,6 +112,8 @@ impl TlsServer {
                     if tls_conn.register(poll) {
                         let conn = Connection::new(index, tls_conn);
                         self.conns.insert(index, conn);
+                        self.conn_peers.insert(index, addr.ip());
+                        *self.per_ip_counts.entry(addr.ip()).or_insert(0) += 1;
                     } else {
                         tls_conn.shutdown();
                         tls_conn.check_status(poll);
@@ -96,6 +131,17 @@ impl TlsServer {
         }
     }
 
+    fn forget(&mut self, index: usize) {
+        if let Some(addr) = self.conn_peers.remove(&index) {
+            if let Some(count) = self.per_ip_counts.get_mut(&addr) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    self.per_ip_counts.remove(&addr);
+                }
+            }
+        }
+    }
+
     fn next_index(&mut self) -> usize {
         let index = self.next_id;
         self.next_id += 1;
@@ -121,6 +167,7 @@ impl TlsServer {
             conn.ready(poll, event, resolver);
             if conn.destroyed() {
                 self.conns.remove(&index);
+                self.forget(index);
                 log::debug!("connection:{} closed, remove from pool", index);
             }
         } else {
@@ -140,6 +187,23 @@ impl TlsServer {
 
         for index in list {
             self.conns.remove(&index);
+            self.forget(index);
+        }
+
+        // The udp backend feeds `METRICS` in the server process, and the wintun client loop
+        // only samples the counters of its own process, so the server's throughput sample
+        // has to be logged from here.
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_metrics_sample);
+        if elapsed > self.metrics_sample_interval {
+            let speed = METRICS.sample(elapsed);
+            log::info!(
+                "server throughput: up {:.1} KB/s, down {:.1} KB/s, {} active connections",
+                speed.up_bytes_per_sec / 1024.0,
+                speed.down_bytes_per_sec / 1024.0,
+                speed.connections
+            );
+            self.last_metrics_sample = now;
         }
     }
 }
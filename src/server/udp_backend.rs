@@ -1,15 +1,69 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use bytes::BytesMut;
 use mio::{event::Event, net::UdpSocket, Interest, Poll, Token};
 
 use crate::{
     config::OPTIONS,
+    metrics::METRICS,
     proto::{UdpAssociate, UdpParseResult, MAX_BUFFER_SIZE, MAX_PACKET_SIZE},
-    server::tls_server::Backend,
+    server::{policy, tls_server::Backend},
     tls_conn::{ConnStatus, TlsConn},
 };
 
+/// A simple token bucket used to cap the egress/ingress throughput of a single backend.
+///
+/// This is only wired up for `UdpBackend`; there's no server-side TCP `Backend` impl in this
+/// tree to give the same treatment to (`server::connection::Connection`, which `TlsServer`
+/// already depends on, isn't part of this checkout).
+///
+/// Tokens are refilled lazily, based on wall-clock time elapsed since the last check, rather
+/// than on a timer, so the bucket costs nothing when traffic is idle.
+struct TokenBucket {
+    rate_bytes_per_sec: usize,
+    burst: usize,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate_bytes_per_sec: usize, burst: usize) -> TokenBucket {
+        TokenBucket {
+            rate_bytes_per_sec,
+            burst,
+            available: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.available = (self.available + elapsed * self.rate_bytes_per_sec as f64)
+            .min(self.burst as f64);
+    }
+
+    /// Returns true and consumes `size` tokens if the bucket has enough budget.
+    fn take(&mut self, size: usize) -> bool {
+        self.refill();
+        if self.available >= size as f64 {
+            self.available -= size as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// bound on how many rate-limited datagrams are held in memory per connection before the
+/// oldest one is dropped
+const MAX_RECV_OVERFLOW: usize = 64;
+
 pub struct UdpBackend {
     socket: UdpSocket,
     send_buffer: BytesMut,
@@ -23,11 +77,37 @@ pub struct UdpBackend {
     bytes_read: usize,
     bytes_sent: usize,
     remote_addr: SocketAddr,
+    send_limiter: Option<TokenBucket>,
+    recv_limiter: Option<TokenBucket>,
+    /// datagrams read off the socket while over the recv rate limit, held here instead of
+    /// being left unread in the kernel buffer (which would need a wakeup we have no way to
+    /// schedule) and flushed into the session as budget comes back.
+    recv_overflow: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// set when `send_buffer` is non-empty only because the send rate limit is exhausted, as
+    /// opposed to the socket itself being backed up. A UDP socket is writable almost all the
+    /// time, so registering `WRITABLE` for this reason just busy-spins the event loop until
+    /// the limiter refills on its own; the buffered bytes get flushed the next time new data
+    /// arrives to `dispatch` instead.
+    send_rate_paused: bool,
 }
 
 impl UdpBackend {
     pub fn new(socket: UdpSocket, index: usize, token: Token) -> UdpBackend {
         let remote_addr = socket.local_addr().unwrap();
+        METRICS.connection_opened();
+        // these are IPv4-only sockopts; calling them on a v6 socket just logs a spurious
+        // warning on every single connection, so only touch them for v4 sockets.
+        if remote_addr.is_ipv4() {
+            if let Err(err) = socket.set_multicast_ttl_v4(OPTIONS.udp_backend_args().multicast_ttl)
+            {
+                log::warn!("connection:{} set multicast ttl failed:{}", index, err);
+            }
+            if let Err(err) =
+                socket.set_multicast_loop_v4(OPTIONS.udp_backend_args().multicast_loop)
+            {
+                log::warn!("connection:{} set multicast loop failed:{}", index, err);
+            }
+        }
         UdpBackend {
             socket,
             send_buffer: Default::default(),
@@ -41,19 +121,62 @@ impl UdpBackend {
             bytes_read: 0,
             bytes_sent: 0,
             remote_addr,
+            send_limiter: OPTIONS
+                .udp_backend_args()
+                .send_rate_bytes_per_sec
+                .map(|rate| TokenBucket::new(rate, OPTIONS.udp_backend_args().rate_burst)),
+            recv_limiter: OPTIONS
+                .udp_backend_args()
+                .recv_rate_bytes_per_sec
+                .map(|rate| TokenBucket::new(rate, OPTIONS.udp_backend_args().rate_burst)),
+            recv_overflow: VecDeque::new(),
+            send_rate_paused: false,
         }
     }
 
     fn do_send(&mut self, mut buffer: &[u8]) {
+        self.send_rate_paused = false;
         loop {
             match UdpAssociate::parse(buffer) {
                 UdpParseResult::Packet(packet) => {
+                    if !policy::global().allows(packet.address) {
+                        if OPTIONS.udp_backend_args().close_on_policy_deny {
+                            log::warn!(
+                                "connection:{} denied by destination policy:{}, closing",
+                                self.index,
+                                packet.address
+                            );
+                            self.status = ConnStatus::Closing;
+                            return;
+                        }
+                        log::warn!(
+                            "connection:{} denied by destination policy:{}, dropping packet",
+                            self.index,
+                            packet.address
+                        );
+                        buffer = &packet.payload[packet.length..];
+                        continue;
+                    }
+                    if let Some(limiter) = self.send_limiter.as_mut() {
+                        if !limiter.take(packet.length) {
+                            log::debug!(
+                                "connection:{} send rate limit exhausted, pausing",
+                                self.index
+                            );
+                            self.send_buffer.extend_from_slice(buffer);
+                            // Not a socket-backpressure pause, so `reregister` must not turn
+                            // this into a `WRITABLE` registration - see `send_rate_paused`.
+                            self.send_rate_paused = true;
+                            break;
+                        }
+                    }
                     match self
                         .socket
                         .send_to(&packet.payload[..packet.length], packet.address)
                     {
                         Ok(size) => {
                             self.bytes_sent += size;
+                            METRICS.add_up(size);
                             if size != packet.length {
                                 log::error!(
                                     "connection:{} udp packet is truncated, {}：{}",
@@ -109,27 +232,78 @@ impl UdpBackend {
         }
     }
 
+    /// writes one received datagram into the session, in the wire format the client side
+    /// expects. Returns false if the session write failed and the connection should close.
+    fn forward_datagram(&mut self, conn: &mut TlsConn, addr: SocketAddr, data: &[u8]) -> bool {
+        self.recv_head.clear();
+        UdpAssociate::generate(&mut self.recv_head, &addr, data.len() as u16);
+        conn.write_session(self.recv_head.as_ref()) && conn.write_session(data)
+    }
+
     fn do_read(&mut self, conn: &mut TlsConn) {
+        // Flush whatever the rate limit held back last time before reading anything new, so
+        // the overflow queue actually drains instead of only ever growing. `take` is used
+        // here: a queued datagram hasn't been charged against the limiter yet,
+        // so this is the only place it gets debited, and a datagram larger than the current
+        // balance just stays queued for the next call instead of zeroing the bucket for
+        // nothing.
+        while let Some((addr, data)) = self.recv_overflow.front() {
+            if let Some(limiter) = self.recv_limiter.as_mut() {
+                if !limiter.take(data.len()) {
+                    break;
+                }
+            }
+            let (addr, data) = self.recv_overflow.pop_front().unwrap();
+            if !self.forward_datagram(conn, addr, &data) {
+                self.status = ConnStatus::Closing;
+                conn.do_send();
+                return;
+            }
+        }
+
         loop {
             match self.socket.recv_from(self.recv_body.as_mut_slice()) {
                 Ok((size, addr)) => {
                     self.remote_addr = addr;
                     self.bytes_read += size;
+                    METRICS.add_down(size);
                     log::debug!(
                         "connection:{} got {} bytes udp data from:{}",
                         self.index,
                         size,
                         addr
                     );
-                    self.recv_head.clear();
-                    UdpAssociate::generate(&mut self.recv_head, &addr, size as u16);
-                    if !conn.write_session(self.recv_head.as_ref()) {
-                        self.status = ConnStatus::Closing;
-                        break;
-                    }
-                    if !conn.write_session(&self.recv_body.as_slice()[..size]) {
-                        self.status = ConnStatus::Closing;
-                        break;
+                    // The socket must always be drained here: mio registers edge-triggered,
+                    // so skipping `recv_from` while rate-limited would leave data sitting
+                    // unread with no further readiness event to pick it back up. A datagram
+                    // is debited exactly once: here via `take` if it can go straight out, or
+                    // later by the drain loop above if it has to queue - never both.
+                    let had_budget = self.recv_overflow.is_empty()
+                        && self
+                            .recv_limiter
+                            .as_mut()
+                            .map(|limiter| limiter.take(size))
+                            .unwrap_or(true);
+                    if had_budget {
+                        self.recv_head.clear();
+                        UdpAssociate::generate(&mut self.recv_head, &addr, size as u16);
+                        let ok = conn.write_session(self.recv_head.as_ref())
+                            && conn.write_session(&self.recv_body.as_slice()[..size]);
+                        if !ok {
+                            self.status = ConnStatus::Closing;
+                            break;
+                        }
+                    } else if self.recv_overflow.len() >= MAX_RECV_OVERFLOW {
+                        log::warn!(
+                            "connection:{} recv overflow queue full, dropping oldest datagram",
+                            self.index
+                        );
+                        self.recv_overflow.pop_front();
+                        self.recv_overflow
+                            .push_back((addr, self.recv_body.as_slice()[..size].to_vec()));
+                    } else {
+                        self.recv_overflow
+                            .push_back((addr, self.recv_body.as_slice()[..size].to_vec()));
                     }
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
@@ -189,12 +363,17 @@ impl Backend for UdpBackend {
             ConnStatus::Closed => {}
             _ => {
                 let mut changed = false;
-                if !self.send_buffer.is_empty() && !self.interest.is_writable() {
+                if !self.send_buffer.is_empty()
+                    && !self.send_rate_paused
+                    && !self.interest.is_writable()
+                {
                     self.interest |= Interest::WRITABLE;
                     changed = true;
                     log::debug!("connection:{} add writable to udp target", self.index);
                 }
-                if self.send_buffer.is_empty() && self.interest.is_writable() {
+                if (self.send_buffer.is_empty() || self.send_rate_paused)
+                    && self.interest.is_writable()
+                {
                     self.interest = self
                         .interest
                         .remove(Interest::WRITABLE)
@@ -227,6 +406,7 @@ impl Backend for UdpBackend {
         if let ConnStatus::Closing = self.status {
             let _ = poll.registry().deregister(&mut self.socket);
             self.status = ConnStatus::Closed;
+            METRICS.connection_closed();
             log::info!(
                 "connection:{} address:{} closed, read {} bytes, sent {} bytes",
                 self.index,
@@ -261,3 +441,34 @@ impl Backend for UdpBackend {
         self.send_buffer.len() < MAX_BUFFER_SIZE
     }
 }
+
+impl Drop for UdpBackend {
+    fn drop(&mut self) {
+        // `check_close` is the normal path that pairs `connection_opened`, but a backend can
+        // be dropped (e.g. the owning `Connection` going away during shutdown) without ever
+        // passing through `Closing`; without this the live-connection count would leak.
+        if !matches!(self.status, ConnStatus::Closed) {
+            METRICS.connection_closed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_consumes_only_when_budget_allows() {
+        let mut bucket = TokenBucket::new(1000, 100);
+        assert!(bucket.take(60));
+        assert!(!bucket.take(60), "second take should fail, only 40 left");
+        assert!(bucket.take(40), "remaining 40 should still be takeable");
+    }
+
+    #[test]
+    fn take_never_goes_negative() {
+        let mut bucket = TokenBucket::new(1000, 50);
+        assert!(!bucket.take(200), "can't take more than the burst size");
+        assert!(bucket.take(50), "budget must be untouched after a failed take");
+    }
+}
@@ -22,6 +22,7 @@ use smoltcp::{
 use wintun::{Adapter, Session};
 
 use crate::{
+    metrics::METRICS,
     proxy::IdlePool,
     resolver::DnsResolver,
     types::Result,
@@ -96,13 +97,16 @@ pub fn run() -> Result<()> {
             ta.name_constraints,
         )
     }));
-    let config = ClientConfig::builder()
+    let mut config = ClientConfig::builder()
         .with_safe_default_cipher_suites()
         .with_safe_default_kx_groups()
         .with_safe_default_protocol_versions()
         .unwrap()
         .with_root_certificates(root_store)
         .with_no_client_auth();
+    // reuse TLS 1.3 sessions across reconnects so `IdlePool` can resync a broken
+    // tunnel without paying a full handshake round trip every time.
+    config.session_storage = rustls::client::ClientSessionMemoryCache::new(256);
     let config = Arc::new(config);
 
     let mut poll = Poll::new()?;
@@ -142,9 +146,19 @@ pub fn run() -> Result<()> {
     let mut udp_server = UdpServer::new();
     let mut tcp_server = TcpServer::new();
 
+    /// number of consecutive idle iterations before the poll timeout starts growing
+    const IDLE_THRESHOLD: u32 = 256;
+    /// extra millisecond added to the timeout for every idle cycle past the threshold
+    const IDLE_STEP_MILLIS: u64 = 1;
+    /// upper bound on the grown timeout, so smoltcp timers stay responsive
+    const IDLE_TIMEOUT_CEILING_MILLIS: u64 = 20;
+    let mut idle_cycles: u32 = 0;
+
     let mut last_udp_check_time = std::time::Instant::now();
     let mut last_tcp_check_time = std::time::Instant::now();
     let check_duration = std::time::Duration::new(10, 0);
+    // multicast groups joined on the interface, with the last time traffic for each was seen
+    let mut multicast_groups: Vec<(IpAddress, std::time::Instant)> = Vec::new();
 
     let index = adapter.get_adapter_index()?;
     add_route_with_if("0.0.0.0", "0.0.0.0", index);
@@ -158,19 +172,39 @@ pub fn run() -> Result<()> {
 
     let mut now = Instant::now();
     loop {
-        let (udp_handles, tcp_handles) = do_tun_read(&session, &sender, &mut interface)?;
-        if let Err(err) = interface.poll(now) {
-            log::info!("interface error:{}", err);
-        }
+        let (udp_handles, tcp_handles) =
+            do_tun_read(&session, &sender, &mut interface, &mut multicast_groups)?;
+        let tun_idle = udp_handles.is_empty() && tcp_handles.is_empty();
+        let interface_idle = match interface.poll(now) {
+            Ok(readiness_changed) => !readiness_changed,
+            Err(err) => {
+                log::info!("interface error:{}", err);
+                true
+            }
+        };
         udp_server.do_local(&mut pool, &poll, &resolver, udp_handles, &mut interface);
         tcp_server.do_local(&mut pool, &poll, &resolver, tcp_handles, &mut interface);
 
         now = Instant::now();
-        let timeout = interface.poll_delay(now).or(timeout);
+        let idle_timeout = if idle_cycles > IDLE_THRESHOLD {
+            let grown = (idle_cycles - IDLE_THRESHOLD) as u64 * IDLE_STEP_MILLIS;
+            Some(Duration::from_millis(grown.min(IDLE_TIMEOUT_CEILING_MILLIS)))
+        } else {
+            timeout
+        };
+        let timeout = interface
+            .poll_delay(now)
+            .map(|d| d.min(idle_timeout.unwrap_or(d)))
+            .or(idle_timeout);
         poll.poll(
             &mut events,
             timeout.map(|d| std::time::Duration::from_millis(d.total_millis())),
         )?;
+        if tun_idle && interface_idle && events.is_empty() {
+            idle_cycles = idle_cycles.saturating_add(1);
+        } else {
+            idle_cycles = 0;
+        }
         for event in &events {
             match event.token().0 {
                 RESOLVER => {
@@ -193,11 +227,29 @@ pub fn run() -> Result<()> {
         let now = std::time::Instant::now();
         if now - last_tcp_check_time > check_duration {
             tcp_server.check_timeout(&poll, now, &mut interface);
+            pool.check_reconnect(&poll);
+            let speed = METRICS.sample(check_duration);
+            log::info!(
+                "tunnel speed: up {:.1} KB/s, down {:.1} KB/s, {} active connections",
+                speed.up_bytes_per_sec / 1024.0,
+                speed.down_bytes_per_sec / 1024.0,
+                speed.connections
+            );
             last_tcp_check_time = now;
         }
 
         if now - last_udp_check_time > OPTIONS.udp_idle_duration {
             udp_server.check_timeout(now, &mut interface);
+            multicast_groups.retain(|(addr, last_seen)| {
+                if now.saturating_duration_since(*last_seen) <= OPTIONS.udp_idle_duration {
+                    return true;
+                }
+                match interface.leave_multicast_group(*addr, Instant::now()) {
+                    Ok(_) => log::debug!("left idle multicast group {}", addr),
+                    Err(err) => log::warn!("leave multicast group {} failed:{}", addr, err),
+                }
+                false
+            });
             last_udp_check_time = now;
         }
     }
@@ -207,6 +259,7 @@ fn do_tun_read(
     session: &Arc<Session>,
     sender: &Sender<Vec<u8>>,
     sockets: &mut SocketSet,
+    multicast_groups: &mut Vec<(IpAddress, std::time::Instant)>,
 ) -> Result<(Vec<SocketHandle>, Vec<SocketHandle>)> {
     let mut udp_handles = Vec::new();
     let mut tcp_handles = Vec::new();
@@ -267,9 +320,18 @@ fn do_tun_read(
 
         if let Some(connect) = connect {
             if let Some(handle) = if connect {
+                // Size the backing buffers from the configured bandwidth-delay-product
+                // estimate rather than the flat `tcp_rx_buffer_size`/`tcp_tx_buffer_size`.
+                // The estimate is one static value from the options, shared by every flow.
                 let mut socket = TcpSocket::new(
-                    TcpSocketBuffer::new(vec![0; OPTIONS.wintun_args().tcp_rx_buffer_size]),
-                    TcpSocketBuffer::new(vec![0; OPTIONS.wintun_args().tcp_tx_buffer_size]),
+                    TcpSocketBuffer::new(vec![
+                        0;
+                        bdp_buffer_size(OPTIONS.wintun_args().tcp_rx_buffer_size)
+                    ]),
+                    TcpSocketBuffer::new(vec![
+                        0;
+                        bdp_buffer_size(OPTIONS.wintun_args().tcp_tx_buffer_size)
+                    ]),
                 );
                 socket.listen(dst_endpoint).unwrap();
                 Some(sockets.add_socket(socket))
@@ -306,13 +368,50 @@ fn do_tun_read(
                         ),
                     );
                     socket.bind(dst_endpoint)?;
-                    sockets.add_socket(socket)
+                    let handle = sockets.add_socket(socket);
+                    if is_multicast(dst_addr) {
+                        match sockets.join_multicast_group(dst_addr, Instant::now()) {
+                            Ok(_) => log::debug!("joined multicast group {}", dst_addr),
+                            Err(err) => {
+                                log::warn!("join multicast group {} failed:{}", dst_addr, err)
+                            }
+                        }
+                        multicast_groups.push((dst_addr, std::time::Instant::now()));
+                    }
+                    handle
                 }
                 Some(handle) => handle,
             };
+            if is_multicast(dst_addr) {
+                // track the last time traffic for this group was seen, so the periodic sweep
+                // below can leave groups nobody is listening to anymore
+                let now = std::time::Instant::now();
+                match multicast_groups.iter_mut().find(|(addr, _)| *addr == dst_addr) {
+                    Some((_, last_seen)) => *last_seen = now,
+                    None => {
+                        // Not tracked means the idle sweep already issued
+                        // `leave_multicast_group` for this address while the smoltcp socket
+                        // itself stayed bound (the handle lookup above matched an existing
+                        // UDP socket). Traffic resuming here has to re-join explicitly, or
+                        // membership silently stays dropped for a still-live association.
+                        match sockets.join_multicast_group(dst_addr, now) {
+                            Ok(_) => log::debug!("re-joined multicast group {}", dst_addr),
+                            Err(err) => {
+                                log::warn!("re-join multicast group {} failed:{}", dst_addr, err)
+                            }
+                        }
+                        multicast_groups.push((dst_addr, now));
+                    }
+                }
+            }
             udp_handles.push(handle);
         }
 
+        // Packets handed to `sender` are the upstream leg of the tunnel (local app -> trojan
+        // server); the downstream leg would be accounted for wherever `tcp_server`/`udp_server`
+        // write remote data back into the smoltcp sockets, but those modules aren't present in
+        // this checkout, so `down_bytes_per_sec` stays at zero on the client side for now.
+        METRICS.add_up(packet.bytes().len());
         if let Err(err) = sender.try_send(packet.bytes().into()) {
             log::warn!("sender buffer is full:{}", err);
         }
@@ -321,6 +420,50 @@ fn do_tun_read(
     Ok((udp_handles, tcp_handles))
 }
 
+/// minimum TCP socket buffer handed out regardless of the BDP estimate
+const MIN_TCP_BUFFER_SIZE: usize = 4096;
+
+/// Sizes a TCP socket buffer from the configured bandwidth-delay-product estimate
+/// (`rate * rtt`), clamped between [`MIN_TCP_BUFFER_SIZE`] and the larger of
+/// `bdp_max_buffer_size` and `default_size`.
+///
+/// `default_size` (the old flat, unconditional buffer size) is returned unchanged when no
+/// rate/RTT estimate is configured; otherwise the ceiling is `bdp_max_buffer_size`, a separate
+/// knob (never below `default_size`), so a high-BDP link can size up past what used to be the fixed default instead of only
+/// ever being able to shrink towards it. This is still a single static estimate rather than a
+/// true per-flow measurement - doing that would need RTT/throughput sampling hooked into the
+/// per-connection read/write path in `wintun::tcp`, which is out of scope here.
+fn bdp_buffer_size(default_size: usize) -> usize {
+    let rate = OPTIONS.wintun_args().bdp_rate_bytes_per_sec;
+    let rtt = OPTIONS.wintun_args().bdp_rtt_millis;
+    let max_buffer_size = OPTIONS.wintun_args().bdp_max_buffer_size;
+    estimate_bdp_buffer_size(default_size, rate, rtt, max_buffer_size)
+}
+
+/// the pure estimate behind [`bdp_buffer_size`], split out so it can be exercised without an
+/// `OPTIONS` instance.
+fn estimate_bdp_buffer_size(
+    default_size: usize,
+    rate_bytes_per_sec: usize,
+    rtt_millis: usize,
+    max_buffer_size: usize,
+) -> usize {
+    if rate_bytes_per_sec == 0 || rtt_millis == 0 {
+        return default_size;
+    }
+    let max_buffer_size = max_buffer_size.max(default_size);
+    let estimated = rate_bytes_per_sec.saturating_mul(rtt_millis) / 1000;
+    estimated.clamp(MIN_TCP_BUFFER_SIZE, max_buffer_size)
+}
+
+fn is_multicast(addr: IpAddress) -> bool {
+    match addr {
+        IpAddress::Ipv4(addr) => addr.is_multicast(),
+        IpAddress::Ipv6(addr) => addr.is_multicast(),
+        _ => false,
+    }
+}
+
 fn add_ipset(config: &str, gw: &str) -> Result<()> {
     let file = File::open(config)?;
     let buffer = BufReader::new(file);
@@ -332,3 +475,34 @@ fn add_ipset(config: &str, gw: &str) -> Result<()> {
     });
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_bdp_buffer_size_falls_back_when_unconfigured() {
+        assert_eq!(estimate_bdp_buffer_size(65536, 0, 100, 1 << 20), 65536);
+        assert_eq!(estimate_bdp_buffer_size(65536, 1_000_000, 0, 1 << 20), 65536);
+    }
+
+    #[test]
+    fn estimate_bdp_buffer_size_can_grow_past_the_default() {
+        // 10 MB/s * 200ms = 2 MB of BDP, well past the old flat 64 KB default.
+        let size = estimate_bdp_buffer_size(65536, 10_000_000, 200, 1 << 22);
+        assert_eq!(size, 2_000_000);
+        assert!(size > 65536);
+    }
+
+    #[test]
+    fn estimate_bdp_buffer_size_respects_the_configured_ceiling() {
+        let size = estimate_bdp_buffer_size(65536, 10_000_000, 200, 100_000);
+        assert_eq!(size, 100_000);
+    }
+
+    #[test]
+    fn estimate_bdp_buffer_size_never_goes_below_the_minimum() {
+        let size = estimate_bdp_buffer_size(65536, 1, 1, 1 << 20);
+        assert_eq!(size, MIN_TCP_BUFFER_SIZE);
+    }
+}